@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use enum_iterator::Sequence;
 use macroquad::{
     color::{Color, colors::*},
@@ -19,20 +21,117 @@ pub enum FlowDir {
     Right,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence)]
+/// the maximum fill level a liquid cell can hold
+const MAX_FLUID_LEVEL: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 /// blocks within a falling sand world
 pub enum Block {
     Air,
     Stone,
     Sand,
-    Water(FlowDir),
-    Lava(FlowDir),
+    /// a fragile, lightweight block that moving liquid washes away
+    Plant,
+    /// a fluid cell with a flow direction and a fill level in `1..=MAX_FLUID_LEVEL`
+    Water(FlowDir, u8),
+    Lava(FlowDir, u8),
+    /// an infinite fluid source that never runs dry and keeps its neighbors topped up
+    WaterSource,
+    LavaSource,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence)]
+/// the kinds of block the player can place, independent of a placed
+/// liquid's flow direction or fill level
+pub enum BlockKind {
+    Air,
+    Stone,
+    Sand,
+    Plant,
+    Water,
+    Lava,
+    WaterSource,
+    LavaSource,
+}
+
+impl BlockKind {
+    /// creates a freshly placed, full-level block of this kind
+    pub fn to_block(self) -> Block {
+        match self {
+            BlockKind::Air => Block::Air,
+            BlockKind::Stone => Block::Stone,
+            BlockKind::Sand => Block::Sand,
+            BlockKind::Plant => Block::Plant,
+            BlockKind::Water => Block::Water(FlowDir::None, MAX_FLUID_LEVEL),
+            BlockKind::Lava => Block::Lava(FlowDir::None, MAX_FLUID_LEVEL),
+            BlockKind::WaterSource => Block::WaterSource,
+            BlockKind::LavaSource => Block::LavaSource,
+        }
+    }
 }
 
 impl Block {
     /// returns if this block is static and not updated
     fn is_static(&self) -> bool {
-        return matches!(self, Block::Air | Block::Stone);
+        matches!(self, Block::Air | Block::Stone | Block::Plant | Block::WaterSource | Block::LavaSource)
+    }
+
+    /// returns if moving liquid sweeps this block away instead of being
+    /// blocked by it
+    fn can_wash_away(&self) -> bool {
+        matches!(self, Block::Plant)
+    }
+
+    /// returns if this block is an infinite fluid source
+    fn is_source(&self) -> bool {
+        matches!(self, Block::WaterSource | Block::LavaSource)
+    }
+
+    /// returns if this block is lava, flowing or an infinite source
+    fn is_lava(&self) -> bool {
+        matches!(self, Block::Lava(..) | Block::LavaSource)
+    }
+
+    /// returns if this block is water, flowing or an infinite source
+    fn is_water(&self) -> bool {
+        matches!(self, Block::Water(..) | Block::WaterSource)
+    }
+
+    /// returns a freshly flowing, full-level liquid of the kind this source produces
+    fn source_kind(&self) -> Option<Block> {
+        match self {
+            Block::WaterSource => Some(Block::Water(FlowDir::None, MAX_FLUID_LEVEL)),
+            Block::LavaSource => Some(Block::Lava(FlowDir::None, MAX_FLUID_LEVEL)),
+            _ => None,
+        }
+    }
+
+    /// returns if this source would promote the given flowing liquid into a source
+    fn promotes_flow_of(&self, flowing: &Block) -> bool {
+        matches!(
+            (self, flowing),
+            (Block::WaterSource, Block::Water(..)) | (Block::LavaSource, Block::Lava(..))
+        )
+    }
+
+    /// returns if this block provides enough pressure to hold up a
+    /// full same-kind liquid cell resting directly on top of it: either
+    /// an infinite source, or another already-full cell of the same liquid
+    fn supports_pressure(&self, liquid: &Block) -> bool {
+        match self {
+            Block::WaterSource => matches!(liquid, Block::Water(..)),
+            Block::LavaSource => matches!(liquid, Block::Lava(..)),
+            _ => self.same_kind(liquid) && self.fluid_level() == Some(MAX_FLUID_LEVEL),
+        }
+    }
+
+    /// converts a flowing liquid into the matching source block
+    fn to_source(self) -> Block {
+        match self {
+            Block::Water(..) => Block::WaterSource,
+            Block::Lava(..) => Block::LavaSource,
+            _ => unreachable!(),
+        }
     }
 
     /// returns if moving into the other block is a valid operation
@@ -45,8 +144,11 @@ impl Block {
     fn density(&self) -> i32 {
         match self {
             Self::Air => 0,
-            Self::Water(_) => 1,
-            Self::Lava(_) => 2,
+            Self::Water(..) => 1,
+            Self::WaterSource => 1,
+            Self::Lava(..) => 2,
+            Self::LavaSource => 2,
+            Self::Plant => 2,
             Self::Sand => 3,
             Self::Stone => 100,
         }
@@ -56,21 +158,31 @@ impl Block {
     fn state(&self) -> State {
         match self {
             Self::Air => State::Gas,
-            Self::Water(_) => State::Liquid,
-            Self::Lava(_) => State::Liquid,
+            Self::Water(..) | Self::WaterSource => State::Liquid,
+            Self::Lava(..) | Self::LavaSource => State::Liquid,
             Self::Sand => State::Solid,
             Self::Stone => State::Solid,
+            Self::Plant => State::Solid,
         }
     }
 
-    /// returns the flow direction of the liquid
-    fn get_flow_dir(&self) -> FlowDir {
+    /// returns the fill level of a liquid block, or `None` if this isn't one
+    fn fluid_level(&self) -> Option<u8> {
         match self {
-            Block::Water(flow_dir) | Block::Lava(flow_dir) => *flow_dir,
-            _ => FlowDir::None
+            Block::Water(_, level) | Block::Lava(_, level) => Some(*level),
+            _ => None,
         }
     }
 
+    /// returns if both blocks are the same kind of liquid, ignoring flow
+    /// direction and fill level
+    fn same_kind(&self, other: &Block) -> bool {
+        matches!(
+            (self, other),
+            (Block::Water(..), Block::Water(..)) | (Block::Lava(..), Block::Lava(..))
+        )
+    }
+
     // returns the color of the block
     pub fn get_color(&self) -> Color {
         match self {
@@ -80,24 +192,63 @@ impl Block {
             Block::Sand => {
                 YELLOW
             }
-            Block::Water(_) => {
+            Block::Plant => {
+                GREEN
+            }
+            Block::Water(_, level) => {
+                Color { a: Self::level_alpha(*level), ..BLUE }
+            }
+            Self::Lava(_, level) => {
+                Color { a: Self::level_alpha(*level), ..RED }
+            }
+            Block::WaterSource => {
                 BLUE
             }
-            Self::Lava(_) => {
+            Block::LavaSource => {
                 RED
             }
             _ => unimplemented!("Block Type: {self:?} does not have a color")
         }
     }
 
-    /// creates a copy of the liquid with a different flow direction
-    fn clone_with_flow(&self, flowing: FlowDir) -> Block {
+    /// maps a fill level to the alpha a liquid should render with, so
+    /// shallow pools look lighter than full ones
+    fn level_alpha(level: u8) -> f32 {
+        (level as f32 / MAX_FLUID_LEVEL as f32).clamp(0.2, 1.0)
+    }
+
+    /// creates a copy of the liquid with a different flow direction and fill level
+    fn with_state(&self, flow_dir: FlowDir, level: u8) -> Block {
         match self {
-            Block::Water(_) => Block::Water(flowing),
-            Block::Lava(_) => Block::Lava(flowing),
+            Block::Water(..) => Block::Water(flow_dir, level),
+            Block::Lava(..) => Block::Lava(flow_dir, level),
             _ => unreachable!()
         }
     }
+
+    /// returns if this block has a defined reaction when touching the other
+    /// block; covers fluid sources as well as flowing liquid, so a fountain
+    /// built against an opposing pool hardens instead of sitting there
+    /// forever
+    fn reacts_with(&self, other: &Block) -> bool {
+        (self.is_lava() && other.is_water()) || (self.is_water() && other.is_lava())
+    }
+
+    /// reacts this block against the other block, returning the new
+    /// (self, other) pair to replace them with; two flowing cells react
+    /// asymmetrically (lava hardens, water is consumed), but if either
+    /// side is an infinite source there's nothing left to flow once the
+    /// contact point hardens, so both sides turn to stone
+    fn react(&self, other: &Block) -> (Block, Block) {
+        match (self, other) {
+            (Block::Lava(..), Block::Water(..)) => (Block::Stone, Block::Air),
+            (Block::Water(..), Block::Lava(..)) => (Block::Air, Block::Stone),
+            _ if (self.is_lava() && other.is_water()) || (self.is_water() && other.is_lava()) => {
+                (Block::Stone, Block::Stone)
+            }
+            _ => (*self, *other),
+        }
+    }
 }
 
 /// falling sand world
@@ -105,6 +256,13 @@ pub struct World {
     blocks: Vec<Vec<Block>>,
     width: usize,
     height: usize,
+    /// cells that might still move, double-buffered between ticks so idle
+    /// regions of the world cost nothing to simulate
+    active: HashSet<(usize, usize)>,
+    /// every fluid source currently on the board, tracked separately from
+    /// `active` since a source must keep topping up its neighbors forever,
+    /// long after it and the pool around it have gone dormant
+    sources: HashSet<(usize, usize)>,
 }
 
 impl World {
@@ -115,69 +273,309 @@ impl World {
             blocks,
             width,
             height,
+            active: HashSet::new(),
+            sources: HashSet::new(),
         }
     }
 
     /// updates the world state
     pub fn update(&mut self) {
-        // list of block positions that have already been updated this state
-        // mainly used for blocks that move side-to-side
-        let mut updated = Vec::new();
-
-        for y in (0..self.height).rev() {
-            for x in 0..self.width {
-                let block = self.blocks[y][x];
-                if block.is_static() || updated.contains(&(x, y)) {
-                    continue;
+        // cells placed or woken since the last tick may touch a reactive
+        // neighbor without ever moving, so react them before the active
+        // set is drained below
+        self.apply_reactions();
+
+        // process lower rows first so a cell that falls doesn't get
+        // reprocessed after landing in a row that's already been handled
+        let mut current: Vec<(usize, usize)> = self.active.drain().collect();
+        current.sort_unstable_by_key(|&(_, y)| std::cmp::Reverse(y));
+
+        let mut next = HashSet::new();
+
+        for (x, y) in current {
+            let block = self.blocks[y][x];
+            if block.is_static() {
+                continue;
+            }
+
+            match block.state() {
+                State::Solid => {
+                    self.apply_gravity(x, y, &mut next);
+                }
+                State::Liquid => {
+                    self.update_liquid(x, y, &mut next);
+                }
+                _ => unimplemented!("Block Type: {block:?} is unimplemented!")
+            }
+        }
+
+        self.active = next;
+
+        self.apply_reactions();
+        self.apply_sources();
+    }
+
+    /// marks a position and its 8 surrounding neighbors as active for the next tick
+    fn wake(width: usize, height: usize, x: usize, y: usize, next: &mut HashSet<(usize, usize)>) {
+        next.extend(Self::neighborhood(x, y, width, height));
+    }
+
+    /// returns the 3x3 neighborhood of a position (including itself), clipped to bounds
+    fn neighborhood(x: usize, y: usize, width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+        (-1i32..=1)
+            .flat_map(|dy| (-1i32..=1).map(move |dx| (dx, dy)))
+            .filter_map(move |(dx, dy)| {
+                let nx = x as i32 + dx;
+                let ny = y as i32 + dy;
+
+                if nx >= 0 && ny >= 0 && (nx as usize) < width && (ny as usize) < height {
+                    Some((nx as usize, ny as usize))
+                } else {
+                    None
                 }
+            })
+    }
 
-                match block.state() {
-                    State::Solid => {
-                        self.apply_gravity(x, y);
+    /// updates a single liquid cell: mass first flows straight down into
+    /// whatever's below, then equalizes with same-kind liquid neighbors
+    fn update_liquid(&mut self, x: usize, y: usize, next: &mut HashSet<(usize, usize)>) {
+        let block = self.blocks[y][x];
+        let Some(level) = block.fluid_level() else {
+            return;
+        };
+
+        if level == 0 {
+            self.blocks[y][x] = Block::Air;
+            Self::wake(self.width, self.height, x, y, next);
+            return;
+        }
+
+        if y < self.height - 1 {
+            let below = self.blocks[y + 1][x];
+
+            if let Some(below_level) = below.fluid_level() {
+                if block.same_kind(&below) && below_level < MAX_FLUID_LEVEL {
+                    let moving = (MAX_FLUID_LEVEL - below_level).min(level);
+
+                    self.blocks[y + 1][x] = below.with_state(FlowDir::None, below_level + moving);
+
+                    let remaining = level - moving;
+                    self.blocks[y][x] = if remaining == 0 {
+                        Block::Air
+                    } else {
+                        block.with_state(FlowDir::None, remaining)
+                    };
+
+                    Self::wake(self.width, self.height, x, y, next);
+                    Self::wake(self.width, self.height, x, y + 1, next);
+
+                    if remaining == 0 {
+                        return;
                     }
-                    State::Liquid => {
-                        let flow_dir = block.get_flow_dir();
-
-                        // Move Side-to-Side if the water didn't flow downwards
-                        if !self.apply_gravity(x, y) {
-                            let mut positions = Vec::new();
-
-                            if x > 0 && block.can_move_to(self.blocks[y][x-1]) {
-                                positions.push(x-1);
-                            }
-                            if x < self.width-1 && block.can_move_to(self.blocks[y][x+1]) {
-                                positions.push(x+1);
-                            }
-
-                            // remove other direction if continuing flow direction is possible
-                            if flow_dir == FlowDir::Left && positions.contains(&(x-1)) && positions.len() > 1 {
-                                positions.remove(1);
-                            } else if flow_dir == FlowDir::Right && positions.contains(&(x+1)) && positions.len() > 1 {
-                                positions.remove(0);
-                            }
-
-                            if let Some(&position) = positions.choose() {
-                                let flowing = if position < x {
-                                    FlowDir::Left
-                                } else {
-                                    FlowDir::Right
-                                };
-
-                                self.blocks[y][x] = self.blocks[y][position];
-                                self.blocks[y][position] = block.clone_with_flow(flowing);
-
-                                updated.push((position, y));
-                            }
-                        }
+                }
+            } else if below.can_wash_away() {
+                // flowing liquid sweeps the fragile block away instead of
+                // resting on top of it
+                self.blocks[y + 1][x] = block;
+                self.blocks[y][x] = Block::Air;
+
+                Self::wake(self.width, self.height, x, y, next);
+                Self::wake(self.width, self.height, x, y + 1, next);
+
+                return;
+            } else if block.can_move_to(below) {
+                // empty space (or a lighter block) below, the whole cell falls
+                self.blocks[y + 1][x] = block;
+                self.blocks[y][x] = below;
+
+                Self::wake(self.width, self.height, x, y, next);
+                Self::wake(self.width, self.height, x, y + 1, next);
+
+                return;
+            }
+        }
+
+        let block = self.blocks[y][x];
+        let Some(level) = block.fluid_level() else {
+            return;
+        };
+
+        self.equalize_liquid(x, y, block, level, next);
+    }
+
+    /// spreads a liquid's level out with its left/right neighbors, treating
+    /// open air as an empty (level 0) neighbor so a lone cell spreads
+    /// outward instead of sitting as a stuck column, until they differ by
+    /// at most one unit; a cell that's genuinely pressurized from below (by
+    /// a source or another full cell of the same liquid) and still full
+    /// after spreading sideways overflows one unit upward, letting water climb back up
+    /// to its source height under pressure
+    fn equalize_liquid(&mut self, x: usize, y: usize, block: Block, mut level: u8, next: &mut HashSet<(usize, usize)>) {
+        let mut sides = [x.checked_sub(1), Some(x + 1)];
+        sides.shuffle();
+
+        for side in sides.into_iter().flatten() {
+            if side >= self.width {
+                continue;
+            }
+
+            let neighbor = self.blocks[y][side];
+
+            if neighbor.can_wash_away() {
+                // flowing liquid sweeps the fragile block away instead of
+                // being blocked by it, same as the straight-down case; use
+                // the current remaining level, not the level this cell
+                // started the tick with, or mass gets duplicated
+                self.blocks[y][side] = block.with_state(FlowDir::None, level);
+                self.blocks[y][x] = Block::Air;
+
+                Self::wake(self.width, self.height, x, y, next);
+                Self::wake(self.width, self.height, side, y, next);
+
+                return;
+            }
+
+            let neighbor_level = if neighbor == Block::Air {
+                Some(0)
+            } else if block.same_kind(&neighbor) {
+                neighbor.fluid_level()
+            } else {
+                None
+            };
+
+            let Some(neighbor_level) = neighbor_level else {
+                continue;
+            };
+            if level <= neighbor_level + 1 {
+                continue;
+            }
+
+            let moving = (level - neighbor_level) / 2;
+
+            self.blocks[y][side] = block.with_state(FlowDir::None, neighbor_level + moving);
+            level -= moving;
+
+            let flow_dir = if side < x { FlowDir::Left } else { FlowDir::Right };
+            self.blocks[y][x] = block.with_state(flow_dir, level);
+
+            Self::wake(self.width, self.height, x, y, next);
+            Self::wake(self.width, self.height, side, y, next);
+        }
+
+        let pressurized = y < self.height - 1 && self.blocks[y + 1][x].supports_pressure(&block);
+
+        if level >= MAX_FLUID_LEVEL && pressurized && y > 0 && self.blocks[y - 1][x] == Block::Air {
+            self.blocks[y - 1][x] = block.with_state(FlowDir::None, 1);
+            self.blocks[y][x] = block.with_state(FlowDir::None, level - 1);
+
+            Self::wake(self.width, self.height, x, y, next);
+            Self::wake(self.width, self.height, x, y - 1, next);
+        }
+    }
+
+    /// reacts touching blocks, e.g. lava hardening into stone when it
+    /// touches water; only cells that moved or were placed this tick can
+    /// newly start touching a reactive neighbor, so this only needs to
+    /// walk the active set rather than the whole grid
+    fn apply_reactions(&mut self) {
+        let candidates: Vec<(usize, usize)> = self.active.iter().copied().collect();
+
+        for (x, y) in candidates {
+            let block = self.blocks[y][x];
+
+            for (nx, ny) in self.orthogonal_neighbors(x, y) {
+                let neighbor = self.blocks[ny][nx];
+
+                if block.reacts_with(&neighbor) {
+                    let (new_block, new_neighbor) = block.react(&neighbor);
+                    self.blocks[y][x] = new_block;
+                    self.blocks[ny][nx] = new_neighbor;
+
+                    // a source that reacted away no longer produces fluid
+                    if !new_block.is_source() {
+                        self.sources.remove(&(x, y));
                     }
-                    _ => unimplemented!("Block Type: {block:?} is unimplemented!")
+                    if !new_neighbor.is_source() {
+                        self.sources.remove(&(nx, ny));
+                    }
+
+                    Self::wake(self.width, self.height, x, y, &mut self.active);
+                    Self::wake(self.width, self.height, nx, ny, &mut self.active);
+
+                    break;
                 }
             }
         }
     }
 
+    /// refills/spawns liquid next to fluid sources, and promotes a flowing
+    /// cell into a new source once it's fed by two or more sources of the
+    /// same fluid, so dug-out channels self-fill
+    fn apply_sources(&mut self) {
+        let sources: Vec<(usize, usize)> = self.sources.iter().copied().collect();
+        let mut candidates = Vec::new();
+
+        for (x, y) in sources {
+            let block = self.blocks[y][x];
+            let spawned = block.source_kind().unwrap();
+
+            for (nx, ny) in self.orthogonal_neighbors(x, y) {
+                candidates.push((nx, ny));
+                let neighbor = self.blocks[ny][nx];
+
+                if neighbor == Block::Air {
+                    self.blocks[ny][nx] = spawned;
+                    Self::wake(self.width, self.height, nx, ny, &mut self.active);
+                } else if let Some(level) = neighbor.fluid_level() {
+                    if neighbor.same_kind(&spawned) && level < MAX_FLUID_LEVEL {
+                        self.blocks[ny][nx] = neighbor.with_state(FlowDir::None, MAX_FLUID_LEVEL);
+                        Self::wake(self.width, self.height, nx, ny, &mut self.active);
+                    }
+                }
+            }
+        }
+
+        // only a cell orthogonally next to a source can be promoted, so
+        // promotion checks only look at the sources' own neighbors instead
+        // of scanning the whole grid
+        for (x, y) in candidates {
+            let block = self.blocks[y][x];
+            if block.fluid_level().is_none() {
+                continue;
+            }
+
+            let source_neighbors = self
+                .orthogonal_neighbors(x, y)
+                .into_iter()
+                .filter(|&(nx, ny)| self.blocks[ny][nx].promotes_flow_of(&block))
+                .count();
+
+            if source_neighbors >= 2 {
+                self.blocks[y][x] = block.to_source();
+                self.sources.insert((x, y));
+                Self::wake(self.width, self.height, x, y, &mut self.active);
+            }
+        }
+    }
+
+    /// returns the in-bounds orthogonal neighbor coordinates of a position
+    fn orthogonal_neighbors(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
+        let candidates = [
+            (x.checked_sub(1), Some(y)),
+            (Some(x + 1), Some(y)),
+            (Some(x), y.checked_sub(1)),
+            (Some(x), Some(y + 1)),
+        ];
+
+        candidates
+            .into_iter()
+            .filter_map(|(nx, ny)| Some((nx?, ny?)))
+            .filter(|&(nx, ny)| nx < self.width && ny < self.height)
+            .collect()
+    }
+
     /// applies gravity to the specified position and returns if the block there fell
-    fn apply_gravity(&mut self, x: usize, y: usize) -> bool {
+    fn apply_gravity(&mut self, x: usize, y: usize, next: &mut HashSet<(usize, usize)>) -> bool {
         // dont bother checking if on floor
         if y >= self.height-1 {
             return false
@@ -193,6 +591,9 @@ impl World {
             self.blocks[y][x] = self.blocks[below][x];
             self.blocks[below][x] = block;
 
+            Self::wake(self.width, self.height, x, y, next);
+            Self::wake(self.width, self.height, x, below, next);
+
             fell = true;
         } else {
             // Fall to the side
@@ -209,11 +610,14 @@ impl World {
                 self.blocks[y][x] = self.blocks[below][position];
                 self.blocks[below][position] = block;
 
+                Self::wake(self.width, self.height, x, y, next);
+                Self::wake(self.width, self.height, position, below, next);
+
                 fell = true;
             }
         }
 
-        return fell;
+        fell
     }
 
     /// returns if the block at the given position
@@ -228,9 +632,9 @@ impl World {
 
         if to.0 != from.0 {
             let above_to = self.blocks[from.1][to.0];
-            return from_block.can_move_to(to_block) && from_block.can_move_to(above_to);
+            from_block.can_move_to(to_block) && from_block.can_move_to(above_to)
         } else {
-            return from_block.can_move_to(to_block);
+            from_block.can_move_to(to_block)
         }
     }
 
@@ -243,6 +647,13 @@ impl World {
     pub fn set_block(&mut self, x: usize, y: usize, block: Block) {
         if x < self.width && y < self.height {
             self.blocks[y][x] = block;
+            Self::wake(self.width, self.height, x, y, &mut self.active);
+
+            if block.is_source() {
+                self.sources.insert((x, y));
+            } else {
+                self.sources.remove(&(x, y));
+            }
         }
     }
 
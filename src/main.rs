@@ -3,7 +3,7 @@ use std::time::{Duration, Instant};
 use enum_iterator::{first, last, next, previous};
 use macroquad::prelude::*;
 
-use falling_sand::{Block, World};
+use falling_sand::{Block, BlockKind, World};
 
 fn window_conf() -> Conf {
     Conf {
@@ -23,7 +23,7 @@ async fn main() {
     let mut timer = Instant::now();
     let delay = Duration::from_millis(250);
 
-    let mut current_block = Block::Sand;
+    let mut current_block = BlockKind::Sand;
 
     loop {
         clear_background(BLACK);
@@ -36,24 +36,24 @@ async fn main() {
         };
 
         if is_mouse_button_down(MouseButton::Left) {
-            world.set_block(grid_x, grid_y, current_block);
+            world.set_block(grid_x, grid_y, current_block.to_block());
         } else if is_mouse_button_down(MouseButton::Right) {
             world.set_block(grid_x, grid_y, Block::Air);
         }
 
         if mouse_wheel().1 > 0.0 {
-            current_block = if let Some(block) = next(&current_block) {
-                block
+            current_block = if let Some(kind) = next(&current_block) {
+                kind
             } else {
                 // wrap around to start
-                first::<Block>().unwrap()
+                first::<BlockKind>().unwrap()
             };
         } else if mouse_wheel().1 < 0.0 {
-            current_block = if let Some(block) = previous(&current_block) {
-                block
+            current_block = if let Some(kind) = previous(&current_block) {
+                kind
             } else {
                 // wrap around to end
-                last::<Block>().unwrap()
+                last::<BlockKind>().unwrap()
             };
         }
 